@@ -0,0 +1,240 @@
+use std::{path::PathBuf, time::Duration};
+
+/// Most lines `--restore-lines` keeps from the last session.
+const MAX_RESTORE_LINES: usize = 1000;
+
+/// How a key pressed with Alt held is sent to the shell.
+#[derive(Clone, Copy)]
+pub enum MetaMode {
+    /// Prefix the key with ESC, so Alt+f sends `ESC f`.
+    Escape,
+    /// Set the high bit of the key's byte, so Alt+f sends `0xe6`.
+    EightBit,
+}
+
+/// What `--logfile` writes.
+#[derive(Clone, Copy)]
+pub enum LogFormat {
+    /// Every byte read from the pty, escape sequences included.
+    Raw,
+    /// Only the printable text and whitespace, escape sequences removed.
+    Clean,
+}
+
+/// Runtime options, taken from the command line.
+pub struct Config {
+    /// Render nroff style overstrikes (`X\bX`, `_\bX`) as bold and underlined
+    /// text instead of printing both characters.
+    pub overstrike: bool,
+    pub meta: MetaMode,
+    /// Upper bound on repaints per second while output is arriving.
+    pub max_fps: u32,
+    /// Ask before closing the window while a job other than the shell runs.
+    pub confirm_close: bool,
+    /// Follow new output while the view is at the bottom.
+    pub scroll_on_output: bool,
+    /// Bring the cursor back into view when typing from up in the scroll back.
+    pub scroll_on_keystroke: bool,
+    /// Glide the cursor to its new position instead of jumping.
+    pub smooth_cursor: bool,
+    /// Wrap long lines instead of scrolling sideways. `None` keeps whatever
+    /// was last toggled at runtime.
+    pub wrap: Option<bool>,
+    /// Lines of output kept on exit and shown, greyed out, above the next
+    /// session. 0 turns this off.
+    pub restore_lines: usize,
+    /// Program and arguments run in the pty instead of the shell.
+    pub exec: Option<Vec<String>>,
+    /// Reply to ENQ (0x05). Empty sends nothing, like most modern terminals.
+    pub answerback: String,
+    /// Stop repainting entirely after this long without output or input.
+    pub idle_timeout: Option<Duration>,
+    /// How long a new window size has to hold before the pty is resized.
+    pub resize_debounce: Duration,
+    /// File the pty output is appended to as it arrives.
+    pub logfile: Option<PathBuf>,
+    pub log_format: LogFormat,
+    /// Squeeze runs of spaces in commands kept in the history panel.
+    pub collapse_history_spaces: bool,
+    /// Digits of line numbers shown left of each line. `None` hides them.
+    pub line_numbers: Option<usize>,
+    /// RGB color of the line numbers, the weak text color when `None`.
+    pub line_number_color: Option<[u8; 3]>,
+    /// Columns and rows the window is first sized to fit.
+    pub geometry: Option<(u16, u16)>,
+    /// Lines scrolled per mouse wheel notch.
+    pub scroll_lines: f32,
+    /// Glide to the scrolled position instead of jumping whole lines.
+    pub smooth_scroll: bool,
+    /// Leave out blank lines after the last output in exports and the
+    /// output kept for the next session.
+    pub trim_blank_lines: bool,
+    /// Add commands run from the quick command bar to the history panel.
+    pub quick_command_history: bool,
+    /// Most columns and rows the pty is given, however large the window.
+    pub max_cols: u16,
+    pub max_rows: u16,
+    /// Directory the shell (or --exec program) starts in, instead of ours.
+    pub cwd: Option<PathBuf>,
+    /// Print the control characters and modes acted on, and exit.
+    pub list_sequences: bool,
+    /// Close the window as soon as the program exits, instead of leaving its
+    /// last output up until the window is closed.
+    pub close_on_exit: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            overstrike: true,
+            meta: MetaMode::Escape,
+            max_fps: 60,
+            confirm_close: true,
+            scroll_on_output: true,
+            scroll_on_keystroke: true,
+            smooth_cursor: false,
+            wrap: None,
+            restore_lines: 0,
+            exec: None,
+            answerback: String::new(),
+            idle_timeout: None,
+            resize_debounce: Duration::from_millis(100),
+            logfile: None,
+            log_format: LogFormat::Raw,
+            collapse_history_spaces: false,
+            line_numbers: None,
+            line_number_color: None,
+            geometry: None,
+            scroll_lines: 3.0,
+            smooth_scroll: true,
+            trim_blank_lines: true,
+            quick_command_history: true,
+            max_cols: 1000,
+            max_rows: 1000,
+            cwd: None,
+            list_sequences: false,
+            close_on_exit: false,
+        }
+    }
+}
+
+impl Config {
+    pub fn from_args(mut args: impl Iterator<Item = String>) -> Self {
+        let mut config = Config::default();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--list-sequences" => config.list_sequences = true,
+                "--no-overstrike" => config.overstrike = false,
+                "--meta-8bit" => config.meta = MetaMode::EightBit,
+                "--close-on-exit" => config.close_on_exit = true,
+                "--no-confirm-close" => config.confirm_close = false,
+                "--no-scroll-on-output" => config.scroll_on_output = false,
+                "--no-scroll-on-keystroke" => config.scroll_on_keystroke = false,
+                "--smooth-cursor" => config.smooth_cursor = true,
+                "--history-collapse-spaces" => config.collapse_history_spaces = true,
+                "--no-smooth-scroll" => config.smooth_scroll = false,
+                "--scroll-lines" => match args.next().map(|value| value.parse()) {
+                    Some(Ok(lines)) if lines > 0.0 => config.scroll_lines = lines,
+                    _ => println!("--scroll-lines expects a positive number"),
+                },
+                "--no-quick-command-history" => config.quick_command_history = false,
+                "--wrap" => config.wrap = Some(true),
+                "--no-wrap" => config.wrap = Some(false),
+                "--restore-lines" => match args.next().map(|value| value.parse()) {
+                    Some(Ok(lines)) => config.restore_lines = usize::min(lines, MAX_RESTORE_LINES),
+                    _ => println!("--restore-lines expects a number"),
+                },
+                "--max-fps" => match args.next().map(|value| value.parse()) {
+                    Some(Ok(max_fps)) if max_fps > 0 => config.max_fps = max_fps,
+                    _ => println!("--max-fps expects a positive number"),
+                },
+                "--max-cols" => match args.next().map(|value| value.parse()) {
+                    Some(Ok(cols)) if cols > 0 => config.max_cols = cols,
+                    _ => println!("--max-cols expects a positive number"),
+                },
+                "--max-rows" => match args.next().map(|value| value.parse()) {
+                    Some(Ok(rows)) if rows > 0 => config.max_rows = rows,
+                    _ => println!("--max-rows expects a positive number"),
+                },
+                "--idle-timeout" => match args.next().map(|value| value.parse()) {
+                    Some(Ok(seconds)) => config.idle_timeout = Some(Duration::from_secs(seconds)),
+                    _ => println!("--idle-timeout expects a number of seconds"),
+                },
+                "--resize-debounce" => match args.next().map(|value| value.parse()) {
+                    Some(Ok(millis)) => config.resize_debounce = Duration::from_millis(millis),
+                    _ => println!("--resize-debounce expects a number of milliseconds"),
+                },
+                "--geometry" => match args.next().as_deref().and_then(parse_geometry) {
+                    Some(geometry) => config.geometry = Some(geometry),
+                    None => println!("--geometry expects columns and rows like 80x24"),
+                },
+                "--keep-blank-lines" => config.trim_blank_lines = false,
+                "--cwd" => match args.next() {
+                    Some(dir) => config.cwd = Some(PathBuf::from(dir)),
+                    None => println!("--cwd expects a directory"),
+                },
+                "--logfile" => match args.next() {
+                    Some(path) => config.logfile = Some(PathBuf::from(path)),
+                    None => println!("--logfile expects a path"),
+                },
+                "--logfile-clean" => config.log_format = LogFormat::Clean,
+                "--line-numbers" => match args.next().map(|value| value.parse()) {
+                    Some(Ok(width)) if width > 0 => config.line_numbers = Some(width),
+                    _ => println!("--line-numbers expects a positive number of digits"),
+                },
+                "--line-number-color" => match args.next().as_deref().and_then(parse_rgb) {
+                    Some(color) => config.line_number_color = Some(color),
+                    None => println!("--line-number-color expects a color like #808080"),
+                },
+                "--answerback" => match args.next() {
+                    Some(answerback) => config.answerback = answerback,
+                    None => println!("--answerback expects a string"),
+                },
+                // --exec never goes through a shell, this only spells it out
+                "--no-shell" => {}
+                "--exec" => {
+                    let command: Vec<String> = args.by_ref().collect();
+                    if command.is_empty() {
+                        println!("--exec expects a program to run");
+                    } else {
+                        config.exec = Some(command);
+                    }
+                }
+                _ => println!("Ignoring unknown argument: {}", arg),
+            }
+        }
+        config
+    }
+}
+
+/// Parses xterm style `COLSxROWS`, e.g. `80x24`.
+fn parse_geometry(value: &str) -> Option<(u16, u16)> {
+    let (cols, rows) = value.split_once('x')?;
+    match (cols.parse(), rows.parse()) {
+        (Ok(cols), Ok(rows)) if cols > 0 && rows > 0 => Some((cols, rows)),
+        _ => None,
+    }
+}
+
+/// Parses a `#rrggbb` (or `rrggbb`) hex color.
+fn parse_rgb(value: &str) -> Option<[u8; 3]> {
+    let hex = value.strip_prefix('#').unwrap_or(value);
+    if hex.len() != 6 || !hex.is_ascii() {
+        return None;
+    }
+    let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).ok();
+    Some([channel(0)?, channel(2)?, channel(4)?])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn geometry() {
+        assert_eq!(parse_geometry("80x24"), Some((80, 24)));
+        assert_eq!(parse_geometry("0x24"), None);
+        assert_eq!(parse_geometry("80x"), None);
+        assert_eq!(parse_geometry("abc"), None);
+    }
+}
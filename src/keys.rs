@@ -0,0 +1,250 @@
+use crate::config::MetaMode;
+use eframe::egui::{Key, Modifiers};
+
+/// Terminal state that changes what a key sends.
+///
+/// There is no application keypad mode (DECKPAM): egui-winit maps keypad keys
+/// onto the same `Key`s as the top row and drops the key location, so the
+/// keypad's `ESC O p`..`ESC O y` and `ESC O M` can't be told apart from digits
+/// and Enter. Keypad keys always send what their top row counterparts send.
+pub struct KeyModes {
+    /// DECCKM: arrows and Home/End send `ESC O` instead of `ESC [`.
+    pub app_cursor: bool,
+    /// `CSI ?2004h`: pastes are wrapped in `CSI 200~` .. `CSI 201~`.
+    pub bracketed_paste: bool,
+    /// KAM (`CSI 2h`): the keyboard is locked and nothing typed is sent,
+    /// until `CSI 2l` or the user unlocks it with Ctrl+Shift+U.
+    pub keyboard_locked: bool,
+    /// SRM reset (`CSI 12l`): typed input is echoed locally as well as sent.
+    pub local_echo: bool,
+    pub meta: MetaMode,
+}
+
+const PASTE_START: &str = "\x1b[200~";
+const PASTE_END: &str = "\x1b[201~";
+
+/// Encodes pasted text, with newlines sent as CR like a typed Enter. When
+/// bracketed, any markers inside the text are removed first so a paste can't
+/// end the bracketing early and have the rest run as typed input.
+///
+/// Whether a paste is bracketed depends on `modes.bracketed_paste` alone, not
+/// on the text: single and multi-line pastes are treated the same. Output is
+/// read before input every frame, so a shell turning `?2004` off for the
+/// duration of a command affects a paste made in the same frame.
+pub fn encode_paste(text: &str, modes: &KeyModes) -> Vec<u8> {
+    let mut text = text.replace('\n', "\r");
+    if !modes.bracketed_paste {
+        return text.into_bytes();
+    }
+    // Removing one marker can join the text around it into another
+    while text.contains(PASTE_START) || text.contains(PASTE_END) {
+        println!("Warning: removed a bracketed paste marker from pasted text");
+        text = text.replace(PASTE_START, "").replace(PASTE_END, "");
+    }
+    [PASTE_START, &text, PASTE_END].concat().into_bytes()
+}
+
+/// Encodes text typed while Alt is held, either prefixed with ESC (what bash
+/// and most editors expect) or with the high bit of each ASCII byte set.
+pub fn encode_meta(text: &str, meta: MetaMode) -> Vec<u8> {
+    match meta {
+        MetaMode::Escape => [b"\x1b", text.as_bytes()].concat(),
+        MetaMode::EightBit => text
+            .bytes()
+            .map(|b| if b.is_ascii() { b | 0x80 } else { b })
+            .collect(),
+    }
+}
+
+/// Translates a key press into the bytes xterm would send for it. Returns
+/// `None` for keys that arrive as `egui::Event::Text` instead.
+pub fn encode_key(key: Key, modifiers: Modifiers, modes: &KeyModes) -> Option<Vec<u8>> {
+    // xterm's modifier parameter: 1 + Shift(1) + Alt(2) + Ctrl(4)
+    let modifier_param =
+        1 + modifiers.shift as u8 + 2 * modifiers.alt as u8 + 4 * modifiers.ctrl as u8;
+
+    // Keys ending in a letter: `CSI A`, `SS3 A` in application mode, or
+    // `CSI 1;<modifier> A` when modified.
+    let letter_key = |final_byte: u8, app_mode: bool| {
+        if modifier_param > 1 {
+            format!("\x1b[1;{}{}", modifier_param, final_byte as char).into_bytes()
+        } else if app_mode {
+            vec![0x1b, b'O', final_byte]
+        } else {
+            vec![0x1b, b'[', final_byte]
+        }
+    };
+    // Keys ending in a tilde: `CSI <code> ~` or `CSI <code>;<modifier> ~`.
+    let tilde_key = |code: u8| {
+        if modifier_param > 1 {
+            format!("\x1b[{};{}~", code, modifier_param).into_bytes()
+        } else {
+            format!("\x1b[{}~", code).into_bytes()
+        }
+    };
+    // Single byte keys only get the Alt prefix.
+    let plain_key = |byte: u8| {
+        if modifiers.alt {
+            encode_meta(&(byte as char).to_string(), modes.meta)
+        } else {
+            vec![byte]
+        }
+    };
+
+    let bytes = match key {
+        Key::ArrowUp => letter_key(b'A', modes.app_cursor),
+        Key::ArrowDown => letter_key(b'B', modes.app_cursor),
+        Key::ArrowRight => letter_key(b'C', modes.app_cursor),
+        Key::ArrowLeft => letter_key(b'D', modes.app_cursor),
+        Key::Home => letter_key(b'H', modes.app_cursor),
+        Key::End => letter_key(b'F', modes.app_cursor),
+        Key::F1 => letter_key(b'P', true),
+        Key::F2 => letter_key(b'Q', true),
+        Key::F3 => letter_key(b'R', true),
+        Key::F4 => letter_key(b'S', true),
+        Key::Insert => tilde_key(2),
+        Key::Delete => tilde_key(3),
+        Key::PageUp => tilde_key(5),
+        Key::PageDown => tilde_key(6),
+        Key::F5 => tilde_key(15),
+        Key::F6 => tilde_key(17),
+        Key::F7 => tilde_key(18),
+        Key::F8 => tilde_key(19),
+        Key::F9 => tilde_key(20),
+        Key::F10 => tilde_key(21),
+        Key::F11 => tilde_key(23),
+        Key::F12 => tilde_key(24),
+        Key::Enter => plain_key(b'\r'),
+        Key::Tab if modifiers.shift => b"\x1b[Z".to_vec(),
+        Key::Tab => plain_key(b'\t'),
+        Key::Backspace if modifiers.ctrl => plain_key(0x08),
+        Key::Backspace => plain_key(0x7f),
+        Key::Escape => plain_key(0x1b),
+        _ if modifiers.ctrl => plain_key(ctrl_byte(key)?),
+        _ => return None,
+    };
+    Some(bytes)
+}
+
+/// The control character Ctrl+`key` produces, e.g. Ctrl+C is ETX (0x03).
+fn ctrl_byte(key: Key) -> Option<u8> {
+    match key {
+        Key::Space | Key::Num2 => Some(0x00),
+        Key::OpenBracket => Some(0x1b),
+        Key::Backslash => Some(0x1c),
+        Key::CloseBracket => Some(0x1d),
+        Key::Slash | Key::Minus => Some(0x1f),
+        _ => match key.name().as_bytes() {
+            [letter] if letter.is_ascii_uppercase() => Some(letter & 0x1f),
+            _ => None,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn modes() -> KeyModes {
+        KeyModes {
+            app_cursor: false,
+            bracketed_paste: false,
+            keyboard_locked: false,
+            local_echo: false,
+            meta: MetaMode::Escape,
+        }
+    }
+
+    fn key(key: Key, modifiers: Modifiers) -> Option<Vec<u8>> {
+        encode_key(key, modifiers, &modes())
+    }
+
+    #[test]
+    fn meta() {
+        assert_eq!(encode_meta("f", MetaMode::Escape), b"\x1bf");
+        assert_eq!(encode_meta("f", MetaMode::EightBit), [0xe6]);
+        // Non-ASCII is left alone rather than corrupted
+        assert_eq!(encode_meta("é", MetaMode::EightBit), "é".as_bytes());
+    }
+
+    #[test]
+    fn alt_keys() {
+        assert_eq!(key(Key::Enter, Modifiers::ALT).unwrap(), b"\x1b\r");
+        assert_eq!(
+            key(Key::F, Modifiers::CTRL | Modifiers::ALT).unwrap(),
+            b"\x1b\x06"
+        );
+    }
+
+    #[test]
+    fn paste() {
+        let bracketed = KeyModes {
+            bracketed_paste: true,
+            ..modes()
+        };
+        assert_eq!(encode_paste("a\nb", &modes()), b"a\rb");
+        assert_eq!(encode_paste("a\nb", &bracketed), b"\x1b[200~a\rb\x1b[201~");
+    }
+
+    #[test]
+    fn paste_markers_are_removed() {
+        let bracketed = KeyModes {
+            bracketed_paste: true,
+            ..modes()
+        };
+        assert_eq!(
+            encode_paste("ls\x1b[201~rm -rf ~\n", &bracketed),
+            b"\x1b[200~lsrm -rf ~\r\x1b[201~"
+        );
+        // Removing the inner marker joins the outer halves into another one
+        assert_eq!(
+            encode_paste("a\x1b[20\x1b[201~1~b", &bracketed),
+            b"\x1b[200~ab\x1b[201~"
+        );
+        // Unbracketed pastes are passed through as they are
+        assert_eq!(encode_paste("a\x1b[201~b", &modes()), b"a\x1b[201~b");
+    }
+
+    #[test]
+    fn arrows() {
+        assert_eq!(key(Key::ArrowUp, Modifiers::NONE).unwrap(), b"\x1b[A");
+        let app_cursor = KeyModes {
+            app_cursor: true,
+            ..modes()
+        };
+        assert_eq!(
+            encode_key(Key::ArrowUp, Modifiers::NONE, &app_cursor).unwrap(),
+            b"\x1bOA"
+        );
+        // Modified arrows are the same in either cursor key mode
+        assert_eq!(key(Key::ArrowUp, Modifiers::CTRL).unwrap(), b"\x1b[1;5A");
+        assert_eq!(
+            encode_key(Key::ArrowLeft, Modifiers::SHIFT, &app_cursor).unwrap(),
+            b"\x1b[1;2D"
+        );
+    }
+
+    #[test]
+    fn tilde_keys() {
+        assert_eq!(key(Key::Delete, Modifiers::NONE).unwrap(), b"\x1b[3~");
+        assert_eq!(key(Key::PageUp, Modifiers::CTRL).unwrap(), b"\x1b[5;5~");
+        assert_eq!(
+            key(Key::F5, Modifiers::SHIFT | Modifiers::ALT).unwrap(),
+            b"\x1b[15;4~"
+        );
+    }
+
+    #[test]
+    fn shift_tab() {
+        assert_eq!(key(Key::Tab, Modifiers::NONE).unwrap(), b"\t");
+        assert_eq!(key(Key::Tab, Modifiers::SHIFT).unwrap(), b"\x1b[Z");
+    }
+
+    #[test]
+    fn ctrl_letters() {
+        assert_eq!(key(Key::C, Modifiers::CTRL).unwrap(), [0x03]);
+        assert_eq!(key(Key::Z, Modifiers::CTRL).unwrap(), [0x1a]);
+        assert_eq!(key(Key::Space, Modifiers::CTRL).unwrap(), [0x00]);
+        assert_eq!(key(Key::C, Modifiers::NONE), None);
+    }
+}
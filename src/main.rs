@@ -1,57 +1,182 @@
+mod config;
+mod keys;
+mod sequences;
+
+use config::{Config, LogFormat};
 use eframe::egui;
+use keys::KeyModes;
 use nix::{
     errno::Errno,
     fcntl::{fcntl, FcntlArg, OFlag},
-    pty::{forkpty, ForkptyResult},
+    poll::{poll, PollFd, PollFlags, PollTimeout},
+    pty::{forkpty, ForkptyResult, Winsize},
+    sys::signal::{kill, Signal},
+    sys::wait::{waitpid, WaitPidFlag, WaitStatus},
+    unistd::Pid,
 };
+use sequences::{Control, EscapeStripper, Mode};
 
 use core::f32;
 use std::{
-    ffi::CStr,
+    convert::Infallible,
+    ffi::{CStr, CString},
+    fs::{File, OpenOptions},
+    io::{BufWriter, Write},
     os::fd::{AsFd, AsRawFd, OwnedFd},
     process::exit,
+    sync::mpsc,
+    time::{Duration, Instant},
 };
 
+nix::ioctl_write_ptr_bad!(tiocswinsz, nix::libc::TIOCSWINSZ, Winsize);
+
+// Emulator shortcuts all use Ctrl+Shift and are consumed before input is
+// forwarded. Every other key, Escape and Tab included, goes to the program
+// running in the pty.
+
+/// Scrolls the view back to the cursor after reading through the scroll back.
+const JUMP_TO_CURSOR: egui::KeyboardShortcut = egui::KeyboardShortcut::new(
+    egui::Modifiers::CTRL.plus(egui::Modifiers::SHIFT),
+    egui::Key::J,
+);
+
+/// Saves the terminal area as a PNG in the working directory.
+const SCREENSHOT: egui::KeyboardShortcut = egui::KeyboardShortcut::new(
+    egui::Modifiers::CTRL.plus(egui::Modifiers::SHIFT),
+    egui::Key::S,
+);
+
+/// Saves the whole scroll back as an HTML page in the working directory.
+const EXPORT_HTML: egui::KeyboardShortcut = egui::KeyboardShortcut::new(
+    egui::Modifiers::CTRL.plus(egui::Modifiers::SHIFT),
+    egui::Key::E,
+);
+
+/// Opens or closes the quick command bar.
+const QUICK_COMMAND: egui::KeyboardShortcut = egui::KeyboardShortcut::new(
+    egui::Modifiers::CTRL.plus(egui::Modifiers::SHIFT),
+    egui::Key::P,
+);
+
+/// Switches between wrapping long lines and scrolling sideways to see them.
+const TOGGLE_WRAP: egui::KeyboardShortcut = egui::KeyboardShortcut::new(
+    egui::Modifiers::CTRL.plus(egui::Modifiers::SHIFT),
+    egui::Key::W,
+);
+
+/// Unlocks the keyboard after the program has locked it with KAM, which
+/// stray output such as a binary file can do too.
+const UNLOCK_KEYBOARD: egui::KeyboardShortcut = egui::KeyboardShortcut::new(
+    egui::Modifiers::CTRL.plus(egui::Modifiers::SHIFT),
+    egui::Key::U,
+);
+
+/// Storage key the wrap setting is persisted under.
+const WRAP_KEY: &str = "wrap";
+
+/// Storage key the end of the last session's output is persisted under.
+const RESTORE_KEY: &str = "restored_output";
+
+/// Largest screenshot saved, in points along either side.
+const MAX_SCREENSHOT_SIZE: f32 = 8192.0;
+
+/// Fraction of the remaining distance the smooth cursor covers each frame.
+const CURSOR_EASING: f32 = 0.4;
+
+/// Moves longer than this many cells skip the smooth cursor animation.
+const CURSOR_SNAP_CELLS: f32 = 4.0;
+
+/// Smallest the window can be made, leaving room for a few cells next to the
+/// history panel.
+const MIN_WINDOW_SIZE: [f32; 2] = [320.0, 120.0];
+
+/// Id of the history panel, its width is kept in egui's memory under it.
+const HISTORY_PANEL_ID: &str = "history_panel";
+
+/// Starting width of the history panel, the window is sized around it for
+/// `--geometry`.
+const HISTORY_PANEL_WIDTH: f32 = 200.0;
+
+/// Space the central panel's frame leaves around the terminal on each side.
+const TERMINAL_MARGIN: f32 = 8.0;
+
+/// Most output read from the pty in one frame.
+const MAX_READ_PER_FRAME: usize = 64 * 1024;
+
+/// How long after the last output or keystroke the pty is still polled at the
+/// full frame rate.
+const ACTIVE_PERIOD: Duration = Duration::from_millis(500);
+
+/// Longest the program is waited for once its side of the pty has closed.
+const EXIT_WAIT: Duration = Duration::from_secs(1);
+
+/// Pty polling interval once nothing has happened for `ACTIVE_PERIOD`.
+const IDLE_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
 fn main() {
-    let fd: Option<OwnedFd> = unsafe {
-        let res = forkpty(None, None).unwrap();
+    let config = Config::from_args(std::env::args().skip(1));
+    if config.list_sequences {
+        sequences::print_supported();
+        return;
+    }
+
+    // The window doesn't exist yet, so --geometry goes by the default font
+    let geometry = config.geometry.map(|(cols, rows)| {
+        let (cols, rows) = (cols.min(config.max_cols), rows.min(config.max_rows));
+        let gutter_cols = config.line_numbers.map_or(0, |width| {
+            u16::try_from(width).unwrap_or(u16::MAX).saturating_add(1)
+        });
+        let size = window_size(cols.saturating_add(gutter_cols), rows, default_char_size());
+        let winsize = Winsize {
+            ws_row: rows,
+            ws_col: cols,
+            ws_xpixel: 0,
+            ws_ypixel: 0,
+        };
+        (size, winsize)
+    });
+
+    let pty: Option<(Pid, OwnedFd)> = unsafe {
+        let res = forkpty(geometry.as_ref().map(|(_, winsize)| winsize), None).unwrap();
         match res {
             ForkptyResult::Parent { child, master } => {
                 println!("Parent process. Child PID: {} Master FD: Some_value", child);
                 // File in non blocking mode to avoid freezing issue
                 fcntl(master.as_raw_fd(), FcntlArg::F_SETFL(OFlag::O_NONBLOCK))
                     .expect("Failed to set non-blocking mode");
-                Some(master) // Return the master file descriptor
-            }
-            ForkptyResult::Child => {
-                println!("Child process. Proceeding to execute shell...");
-                let shell_name = CStr::from_bytes_until_nul(b"/bin/bash\0")
-                    .expect("Something went wrong in creating the shell_name");
-                let args: [&CStr; 0] = [];
-
-                // // For standardizing the shell prompts to `$`
-                // // Also solves the issue of double enter on pressing one enter
-                std::env::remove_var("PROMPT_COMMAND");
-                std::env::set_var("PS1", "$");
-                // std::env::set_var("PS1", "\\[\\e[?2004l\\]$ ");
-                //
-                // Disable bracketed paste mode
-                std::env::set_var("TERM", "dumb");
-
-                nix::unistd::execvp(shell_name, &args).unwrap();
-
-                exit(1);
+                Some((child, master)) // Return the shell's pid and the master file descriptor
             }
+            ForkptyResult::Child => run_child(&config),
         }
     };
 
-    if let Some(fd) = fd {
+    if let Some((child, fd)) = pty {
         println!("Fd read was successful");
-        let native_options = eframe::NativeOptions::default();
+        let wakeup_fd = fd.try_clone().expect("Failed to duplicate the master fd");
+        let initial_size = geometry.map(|(size, _)| size);
+        let mut viewport = egui::ViewportBuilder::default().with_min_inner_size(MIN_WINDOW_SIZE);
+        if let Some(size) = initial_size {
+            viewport = viewport.with_inner_size(size);
+        }
+        let native_options = eframe::NativeOptions {
+            viewport,
+            // A size asked for on the command line isn't remembered for later
+            persist_window: initial_size.is_none(),
+            ..Default::default()
+        };
         let _ = eframe::run_native(
             "Termion",
             native_options,
-            Box::new(move |cc| Ok(Box::new(Termion::new(cc, fd)))),
+            Box::new(move |cc| {
+                Ok(Box::new(Termion::new(
+                    cc,
+                    fd,
+                    wakeup_fd,
+                    child,
+                    config,
+                    initial_size,
+                )))
+            }),
         );
         println!("Completed");
     } else {
@@ -59,48 +184,941 @@ fn main() {
     }
 }
 
+/// Runs the shell, or the `--exec` program, in the child side of the pty
+/// after moving to `--cwd`. Only returns if it can't be started.
+fn run_child(config: &Config) -> ! {
+    // // For standardizing the shell prompts to `$`
+    // // Also solves the issue of double enter on pressing one enter
+    std::env::remove_var("PROMPT_COMMAND");
+    std::env::set_var("PS1", "$");
+    // std::env::set_var("PS1", "\\[\\e[?2004l\\]$ ");
+    //
+    // Disable bracketed paste mode
+    std::env::set_var("TERM", "dumb");
+
+    if let Some(dir) = &config.cwd {
+        if let Err(e) = nix::unistd::chdir(dir) {
+            println!(
+                "Failed to change to {}: {}, starting in the home directory",
+                dir.display(),
+                e
+            );
+            if let Some(home) = std::env::var_os("HOME") {
+                let _ = nix::unistd::chdir(home.as_os_str());
+            }
+        }
+    }
+
+    let result = match &config.exec {
+        Some(command) => {
+            println!("Child process. Proceeding to execute {}...", command[0]);
+            exec_command(command)
+        }
+        None => {
+            println!("Child process. Proceeding to execute shell...");
+            let shell_name = CStr::from_bytes_until_nul(b"/bin/bash\0")
+                .expect("Something went wrong in creating the shell_name");
+            let args: [&CStr; 0] = [];
+            nix::unistd::execvp(shell_name, &args)
+        }
+    };
+    let Err(e) = result;
+    println!("Failed to execute: {}", e);
+
+    exit(1);
+}
+
+/// Starts a thread that repaints as soon as the pty has output, for while
+/// repaints are suspended. Each message sent arms it for one wakeup.
+fn spawn_wakeup_thread(fd: OwnedFd, ctx: egui::Context) -> mpsc::Sender<()> {
+    let (sender, receiver) = mpsc::channel::<()>();
+    std::thread::spawn(move || {
+        while receiver.recv().is_ok() {
+            let mut fds = [PollFd::new(fd.as_fd(), PollFlags::POLLIN)];
+            while let Err(e) = poll(&mut fds, PollTimeout::NONE) {
+                if e != Errno::EINTR {
+                    println!("Failed to wait for output: {}", e);
+                    break;
+                }
+            }
+            ctx.request_repaint();
+        }
+    });
+    sender
+}
+
+/// Replaces the child with `command`, without a shell in between.
+fn exec_command(command: &[String]) -> nix::Result<Infallible> {
+    let args = command
+        .iter()
+        .map(|arg| CString::new(arg.as_str()))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| Errno::EINVAL)?;
+    nix::unistd::execvp(&args[0], &args)
+}
+
 struct Termion {
     fd: OwnedFd,
+    child: Pid, // The shell (or --exec program), leads the foreground group when idle
+    config: Config,
     buf: Vec<u8>,
     command_history: Vec<String>, // Store all commands TODO: Add delete button, add persistence
     current_command: String,      // Tracks current command pre enter press
     cursor_pos: (usize, usize),   // Window space and scroll back
     character_size: Option<(f32, f32)>,
+    pixels_per_point: f32,         // Scale the character size was measured at
+    grid_size: Option<(u16, u16)>, // Rows and columns last sent to the pty
+    pending_size: Option<((u16, u16), Instant)>, // Size waiting out the resize debounce
+    jump_to_cursor: bool,          // Scroll the cursor into view on the next frame
+    last_activity: Instant,        // Last time output was read or input was sent
+    close_prompt: Option<String>,  // Running process the user is asked about on close
+    close_confirmed: bool,         // Let the next close request through
+    exited: bool,                  // The program has exited, only its output is left
+    key_modes: KeyModes,
+    terminal_rect: egui::Rect, // Where the terminal was drawn last frame, for screenshots
+    cursor_motion: Option<(egui::Vec2, egui::Vec2)>, // Painted and target cursor offsets
+    wrap: bool,
+    restored_output: String, // End of the last session's output, shown above this one
+    wakeup: Option<mpsc::Sender<()>>, // Arms the wakeup thread, with an idle timeout
+    suspended: bool,         // Repaints are suspended until the next activity
+    log: Option<BufWriter<File>>, // --logfile, flushed after every batch of reads
+    log_stripper: EscapeStripper, // Takes the escape sequences out of a clean log
+    scroll_offset: f32,      // Where the scroll back was scrolled to last frame
+    wheel_lines: f32,        // Stepped mouse wheel movement not scrolled yet, in lines
+    quick_command: Option<String>, // Text in the quick command bar, while it's open
+    pending_input: Vec<u8>,  // Input the pty couldn't take yet, sent before anything newer
+    initial_size: Option<egui::Vec2>, // --geometry window size, applied on the first frame
 }
 
 impl Termion {
-    fn new(cc: &eframe::CreationContext<'_>, fd: OwnedFd) -> Self {
+    fn new(
+        cc: &eframe::CreationContext<'_>,
+        fd: OwnedFd,
+        wakeup_fd: OwnedFd,
+        child: Pid,
+        config: Config,
+        initial_size: Option<egui::Vec2>,
+    ) -> Self {
         let mut font_id = None;
         cc.egui_ctx.style_mut(|style| {
             style.override_text_style = Some(egui::TextStyle::Monospace);
             font_id = Some(style.text_styles[&egui::TextStyle::Monospace].clone())
         });
 
+        let wrap = config
+            .wrap
+            .or_else(|| {
+                cc.storage
+                    .and_then(|storage| eframe::get_value(storage, WRAP_KEY))
+            })
+            .unwrap_or(true);
+        let restored_output = match config.restore_lines {
+            0 => String::new(),
+            _ => cc
+                .storage
+                .and_then(|storage| eframe::get_value(storage, RESTORE_KEY))
+                .unwrap_or_default(),
+        };
+
+        // The window size restored from the last run, and the history panel
+        // width restored with egui's memory, would both undo --geometry
+        if initial_size.is_some() {
+            cc.egui_ctx.data_mut(|data| {
+                data.remove::<egui::containers::panel::PanelState>(egui::Id::new(HISTORY_PANEL_ID))
+            });
+        }
+
+        let wakeup = config
+            .idle_timeout
+            .map(|_| spawn_wakeup_thread(wakeup_fd, cc.egui_ctx.clone()));
+
+        let log = config.logfile.as_ref().and_then(|path| {
+            match OpenOptions::new().create(true).append(true).open(path) {
+                Ok(file) => Some(BufWriter::new(file)),
+                Err(e) => {
+                    println!("Not logging, failed to open {}: {}", path.display(), e);
+                    None
+                }
+            }
+        });
+
         Termion {
             fd,
+            child,
+            key_modes: KeyModes {
+                app_cursor: false,
+                bracketed_paste: false,
+                keyboard_locked: false,
+                local_echo: false,
+                meta: config.meta,
+            },
+            config,
             buf: Vec::new(),
             command_history: Vec::new(),
             current_command: String::new(),
             cursor_pos: (0, 0),
             character_size: None,
+            pixels_per_point: 0.0,
+            grid_size: None,
+            pending_size: None,
+            jump_to_cursor: false,
+            last_activity: Instant::now(),
+            close_prompt: None,
+            close_confirmed: false,
+            exited: false,
+            terminal_rect: egui::Rect::NOTHING,
+            cursor_motion: None,
+            wrap,
+            restored_output,
+            wakeup,
+            suspended: false,
+            log,
+            log_stripper: EscapeStripper::default(),
+            scroll_offset: 0.0,
+            wheel_lines: 0.0,
+            quick_command: None,
+            pending_input: Vec::new(),
+            initial_size,
+        }
+    }
+
+    /// Size of a cell, measured again whenever the font or the scale changes.
+    /// Fonts can be swapped behind the same `FontId`, so the cheap '0' advance
+    /// and row height are compared every frame rather than the `FontId`. Glyphs
+    /// are snapped to physical pixels, so moving to a monitor with another
+    /// scale factor can change the cell size even though it's in points.
+    ///
+    /// Everything else derived from the cell size follows from the returned
+    /// value in the same frame: the grid size and pty winsize in `resize`, and
+    /// the cursor offset. Only the smooth cursor's position, in pixels of the
+    /// old font, has to be dropped here.
+    fn character_size(&mut self, ctx: &egui::Context) -> (f32, f32) {
+        let font_id = ctx.style().text_styles[&egui::TextStyle::Monospace].clone();
+        let probe =
+            ctx.fonts(|fonts| (fonts.glyph_width(&font_id, '0'), fonts.row_height(&font_id)));
+        let pixels_per_point = ctx.pixels_per_point();
+        match self.character_size {
+            Some(character_size)
+                if !needs_measuring(
+                    (character_size, self.pixels_per_point),
+                    (probe, pixels_per_point),
+                ) =>
+            {
+                character_size
+            }
+            _ => {
+                let character_size = get_char_size(ctx, &font_id);
+                self.character_size = Some(character_size);
+                self.pixels_per_point = pixels_per_point;
+                self.cursor_motion = None;
+                character_size
+            }
+        }
+    }
+
+    /// Reads whatever output the pty has ready, retrying reads interrupted by
+    /// a signal. Stops once a read would block, or after `MAX_READ_PER_FRAME`
+    /// bytes so a flood can't stall the frame; the rest is read next frame.
+    /// Returns false once the program in the pty has exited.
+    fn read_output(&mut self) -> bool {
+        let mut buf = [0u8; 4096];
+        let mut total = 0;
+        while total < MAX_READ_PER_FRAME {
+            match nix::unistd::read(self.fd.as_raw_fd(), &mut buf) {
+                Ok(0) => {
+                    println!("EOF reached");
+                    return false;
+                }
+                Ok(read_size) => {
+                    self.push_output(&buf[..read_size]);
+                    self.write_log(&buf[..read_size]);
+                    // Only the program's output is answered, not local echo
+                    let reply = answerback_reply(&buf[..read_size], &self.config.answerback);
+                    self.write_pty(&reply);
+                    total += read_size;
+                }
+                Err(e) if master_gone(e) => return false,
+                Err(Errno::EINTR) => continue,
+                // Nothing more to read yet (EWOULDBLOCK is EAGAIN on Linux)
+                Err(Errno::EAGAIN) => break,
+                Err(e) => {
+                    println!("Read Failed due to: {}", e);
+                    break;
+                }
+            }
+        }
+        if total > 0 {
+            self.flush_log();
+        }
+        true
+    }
+
+    /// Appends output to the `--logfile`, if there is one.
+    fn write_log(&mut self, output: &[u8]) {
+        let Some(log) = &mut self.log else { return };
+        let result = match self.config.log_format {
+            LogFormat::Raw => log.write_all(output),
+            LogFormat::Clean => log.write_all(&self.log_stripper.strip(output)),
+        };
+        if let Err(e) = result {
+            println!("Stopped logging, write failed: {}", e);
+            self.log = None;
+        }
+    }
+
+    fn flush_log(&mut self) {
+        if let Some(Err(e)) = self.log.as_mut().map(|log| log.flush()) {
+            println!("Stopped logging, flush failed: {}", e);
+            self.log = None;
+        }
+    }
+
+    /// Marks the session as over once the program in the pty has exited. Its
+    /// last output stays on screen, with how it ended below, and the window
+    /// closes when asked to, or after this frame with `--close-on-exit`.
+    fn end_session(&mut self) {
+        let status = exit_message(wait_child(self.child));
+        self.buf
+            .extend_from_slice(format!("\n{}\n", status).as_bytes());
+        self.exited = true;
+        self.close_confirmed = true;
+        self.jump_to_cursor = true;
+    }
+
+    /// Whether typed input goes to the pty: not once the program has exited,
+    /// nor while it has locked the keyboard.
+    fn accepts_input(&self) -> bool {
+        !self.exited && !self.key_modes.keyboard_locked
+    }
+
+    fn push_output(&mut self, incoming: &[u8]) {
+        for &c in incoming {
+            match Control::from_byte(c) {
+                Some(Control::LineFeed) => self.cursor_pos = (0, 1 + self.cursor_pos.1),
+                _ => self.cursor_pos = (1 + self.cursor_pos.0, self.cursor_pos.1),
+            }
+        }
+        self.buf.extend_from_slice(incoming);
+        self.last_activity = Instant::now();
+
+        // Look back far enough to catch a mode change split across reads.
+        // Changes seen twice are applied twice, in order, which is harmless.
+        let lookback = incoming.len() + sequences::MODE_LOOKBACK;
+        let recent = &self.buf[self.buf.len().saturating_sub(lookback)..];
+        for (private, number, set) in sequences::mode_changes(recent) {
+            match Mode::from_code(private, number) {
+                Some(Mode::CursorKeys) => self.key_modes.app_cursor = set,
+                Some(Mode::BracketedPaste) => self.key_modes.bracketed_paste = set,
+                Some(Mode::KeyboardLock) => self.key_modes.keyboard_locked = set,
+                Some(Mode::SendReceive) => self.key_modes.local_echo = !set,
+                // Unsupported, see `--list-sequences`
+                None => {}
+            }
+        }
+    }
+
+    /// Writes `bytes` to the pty after any input still pending from earlier.
+    /// What the pty can't take right now is kept and retried next frame, so
+    /// a bracketed paste can't be cut off between its markers.
+    fn write_pty(&mut self, bytes: &[u8]) {
+        self.pending_input.extend_from_slice(bytes);
+        self.flush_input();
+    }
+
+    /// Writes as much of the pending input as the pty takes.
+    fn flush_input(&mut self) {
+        let fd = self.fd.as_fd();
+        drain_input(&mut self.pending_input, |bytes| {
+            nix::unistd::write(fd, bytes)
+        });
+    }
+
+    /// Eases the painted cursor towards `target` when smooth cursor motion is
+    /// on, returning the offset to paint it at this frame.
+    fn animate_cursor(&mut self, target: egui::Vec2, character_size: (f32, f32)) -> egui::Vec2 {
+        if !self.config.smooth_cursor {
+            return target;
+        }
+        let painted = match self.cursor_motion {
+            // A new move before the last one finished snaps, so the cursor
+            // never trails behind typing
+            Some((painted, last_target)) if last_target == target || painted == last_target => {
+                painted
+            }
+            _ => target,
+        };
+        let distance = target - painted;
+        let painted = if distance.length() < 0.5
+            || distance.x.abs() > CURSOR_SNAP_CELLS * character_size.0
+            || distance.y.abs() > CURSOR_SNAP_CELLS * character_size.1
+        {
+            target
+        } else {
+            painted + distance * CURSOR_EASING
+        };
+        self.cursor_motion = Some((painted, target));
+        painted
+    }
+
+    /// Sends the grid size to the pty when it changes, which also raises
+    /// SIGWINCH in the foreground job so it can redraw.
+    ///
+    /// While the window is being dragged the size changes every frame, so a
+    /// new size is only sent once it has held for the resize debounce. The
+    /// first size goes out straight away.
+    fn resize(&mut self, ctx: &egui::Context, rows: u16, cols: u16) {
+        let now = Instant::now();
+        let debounce = self.config.resize_debounce;
+        let Some((rows, cols)) = settled_size(
+            self.grid_size,
+            &mut self.pending_size,
+            (rows, cols),
+            now,
+            debounce,
+        ) else {
+            if let Some((_, since)) = self.pending_size {
+                ctx.request_repaint_after(debounce.saturating_sub(now - since));
+            }
+            return;
+        };
+
+        let winsize = Winsize {
+            ws_row: rows,
+            ws_col: cols,
+            ws_xpixel: 0,
+            ws_ypixel: 0,
+        };
+        match unsafe { tiocswinsz(self.fd.as_raw_fd(), &winsize) } {
+            Ok(_) => self.grid_size = Some((rows, cols)),
+            Err(e) => println!("Failed to resize the pty: {}", e),
+        }
+    }
+
+    /// Crops a screenshot of the window to the terminal and saves it.
+    fn save_screenshot(&self, screen: &egui::ColorImage, pixels_per_point: f32) {
+        let [width, height] = screen.size;
+        let screen_rect = egui::Rect::from_min_size(
+            egui::Pos2::ZERO,
+            egui::vec2(width as f32, height as f32) / pixels_per_point,
+        );
+        let max_rect = egui::Rect::from_min_size(
+            self.terminal_rect.min,
+            egui::Vec2::splat(MAX_SCREENSHOT_SIZE),
+        );
+        let region = self
+            .terminal_rect
+            .intersect(screen_rect)
+            .intersect(max_rect);
+        if !region.is_positive() {
+            println!("Nothing to screenshot");
+            return;
+        }
+
+        let terminal = screen.region(&region, Some(pixels_per_point));
+        let path = format!("termion-{}.png", timestamp());
+        match image::save_buffer(
+            &path,
+            terminal.as_raw(),
+            terminal.size[0] as u32,
+            terminal.size[1] as u32,
+            image::ExtendedColorType::Rgba8,
+        ) {
+            Ok(()) => println!("Saved screenshot to {}", path),
+            Err(e) => println!("Failed to save screenshot: {}", e),
+        }
+    }
+
+    /// Saves the scroll back, styled as it's shown, as a standalone HTML page.
+    fn save_html(&self, style: &egui::Style) {
+        let mut content = self.buf.as_slice();
+        if self.config.trim_blank_lines {
+            content = trim_blank_lines(content);
+        }
+        let output = layout_output(content, self.config.overstrike, None, style);
+        let path = format!("termion-{}.html", timestamp());
+        match std::fs::write(&path, output_to_html(&output, style)) {
+            Ok(()) => println!("Saved HTML export to {}", path),
+            Err(e) => println!("Failed to save HTML export: {}", e),
+        }
+    }
+
+    /// Describes the job in the foreground of the pty, if it isn't the shell.
+    fn foreground_process(&self) -> Option<String> {
+        let pgrp = nix::unistd::tcgetpgrp(self.fd.as_fd()).ok()?;
+        if pgrp == self.child {
+            return None;
         }
+        let name = std::fs::read_to_string(format!("/proc/{}/comm", pgrp))
+            .map(|name| name.trim().to_string())
+            .unwrap_or_else(|_| "A process".to_string());
+        Some(format!("{} (pid {})", name, pgrp))
     }
+
+    /// Shows the quick command bar while it's open. Enter runs the command at
+    /// the shell, whatever is typed at its prompt, and Escape closes the bar.
+    fn show_quick_command(&mut self, ctx: &egui::Context) {
+        let Some(command) = &mut self.quick_command else {
+            return;
+        };
+        let mut run = false;
+        egui::TopBottomPanel::bottom("quick_command").show(ctx, |ui| {
+            ui.horizontal(|ui| {
+                ui.label("Run:");
+                let response =
+                    ui.add(egui::TextEdit::singleline(command).desired_width(f32::INFINITY));
+                // A single line edit gives up focus on Enter
+                run = response.lost_focus()
+                    && ui.input(|input_state| input_state.key_pressed(egui::Key::Enter));
+                response.request_focus();
+            });
+        });
+        if ctx.input(|input_state| input_state.key_pressed(egui::Key::Escape)) {
+            self.quick_command = None;
+            return;
+        }
+        if !run {
+            return;
+        }
+
+        if !self.accepts_input() {
+            println!("Not running the quick command, the terminal isn't taking input");
+            return;
+        }
+        if let Some(process) = self.foreground_process() {
+            println!(
+                "Not running the quick command, {} is in the foreground",
+                process
+            );
+            return;
+        }
+        let Some(command) = self.quick_command.take() else {
+            return;
+        };
+        // Ctrl+E Ctrl+U clears the prompt, Ctrl+Y brings it back after. Always
+        // sent, the prompt can hold a recalled or edited line not in
+        // `current_command`.
+        self.current_command.clear();
+        self.write_pty(format!("\x05\x15{}\n", command).as_bytes());
+        self.last_activity = Instant::now();
+
+        if self.config.quick_command_history {
+            let entries = history_entries(&command, self.config.collapse_history_spaces);
+            self.command_history.extend(entries);
+        }
+    }
+
+    fn show_close_prompt(&mut self, ctx: &egui::Context) {
+        if ctx.input(|input_state| input_state.viewport().close_requested())
+            && self.config.confirm_close
+            && !self.close_confirmed
+        {
+            if let Some(process) = self.foreground_process() {
+                ctx.send_viewport_cmd(egui::ViewportCommand::CancelClose);
+                self.close_prompt = Some(process);
+            }
+        }
+
+        let Some(process) = &self.close_prompt else {
+            return;
+        };
+        let mut close = None;
+        egui::Window::new("Close Termion?")
+            .collapsible(false)
+            .resizable(false)
+            .anchor(egui::Align2::CENTER_CENTER, [0.0, 0.0])
+            .show(ctx, |ui| {
+                ui.label(format!("{} is still running and will be ended.", process));
+                ui.horizontal(|ui| {
+                    if ui.button("Close").clicked() {
+                        close = Some(true);
+                    }
+                    if ui.button("Cancel").clicked() {
+                        close = Some(false);
+                    }
+                });
+            });
+
+        match close {
+            Some(true) => {
+                if let Err(e) = kill(self.child, Signal::SIGHUP) {
+                    println!("Failed to hang up the shell: {}", e);
+                }
+                self.close_confirmed = true;
+                self.close_prompt = None;
+                ctx.send_viewport_cmd(egui::ViewportCommand::Close);
+            }
+            Some(false) => self.close_prompt = None,
+            None => {}
+        }
+    }
+}
+/// Glyphs sampled when measuring a cell. Digits, narrow and wide latin letters
+/// and a box-drawing character catch a font that isn't truly monospace or a
+/// fallback font kicking in for part of the character set.
+const MEASURED_GLYPHS: [char; 9] = ['0', '9', '@', 'M', 'W', 'i', 'l', '_', '─'];
+
+/// Whether the cell size measured at one scale factor is stale, given the
+/// cheap probe of the font and the scale factor this frame.
+fn needs_measuring(
+    (measured, measured_scale): ((f32, f32), f32),
+    (probe, pixels_per_point): ((f32, f32), f32),
+) -> bool {
+    measured != probe || measured_scale != pixels_per_point
 }
-fn get_char_size(cc: &egui::Context) -> (f32, f32) {
-    let font_id = cc.style().text_styles[&egui::TextStyle::Monospace].clone();
+
+fn get_char_size(cc: &egui::Context, font_id: &egui::FontId) -> (f32, f32) {
     let (width, height) = cc.fonts(|fonts| {
-        let layout = fonts.layout(
-            "@".to_string(),
-            font_id,
-            egui::Color32::default(),
-            f32::INFINITY,
-        );
-        (layout.mesh_bounds.width(), layout.mesh_bounds.height())
+        // Advance widths are what the text layout uses to place glyphs, unlike
+        // the mesh bounds which only cover the ink of a single glyph.
+        let width = fonts.glyph_width(font_id, '0');
+        for c in MEASURED_GLYPHS {
+            let glyph_width = fonts.glyph_width(font_id, c);
+            if (glyph_width - width).abs() > 0.01 {
+                println!(
+                    "Warning: '{}' is {} wide instead of {}, the font is not monospace",
+                    c, glyph_width, width
+                );
+            }
+        }
+        (width, fonts.row_height(font_id))
     });
 
     println!("Character dimentions are: {}, {}", width, height);
 
-    return (width, height);
+    (width, height)
+}
+
+/// Cell size of egui's default monospace font, for before the window exists.
+/// Measured with a throwaway context, at one pixel per point like the sizes
+/// the window is given in.
+fn default_char_size() -> (f32, f32) {
+    let ctx = egui::Context::default();
+    ctx.begin_pass(egui::RawInput::default());
+    let font_id = egui::TextStyle::Monospace.resolve(&ctx.style());
+    let character_size = get_char_size(&ctx, &font_id);
+    let _ = ctx.end_pass();
+    character_size
+}
+
+/// Inner size of a window whose terminal fits `cols` by `rows` cells next to
+/// the history panel. Half a cell is added to each so rounding can't lose
+/// one when `grid_size` measures it again.
+fn window_size(cols: u16, rows: u16, character_size: (f32, f32)) -> egui::Vec2 {
+    let cells = egui::vec2(
+        (cols as f32 + 0.5) * character_size.0,
+        (rows as f32 + 0.5) * character_size.1,
+    );
+    cells + egui::vec2(HISTORY_PANEL_WIDTH, 0.0) + egui::Vec2::splat(2.0 * TERMINAL_MARGIN)
+}
+
+/// What to send back for the ENQs in `output`, which ask the terminal to
+/// identify itself: the answerback once for each.
+fn answerback_reply(output: &[u8], answerback: &str) -> Vec<u8> {
+    let enquiries = output
+        .iter()
+        .filter(|&&c| Control::from_byte(c) == Some(Control::Enquiry))
+        .count();
+    answerback.repeat(enquiries).into_bytes()
+}
+
+/// Waits for the child once the pty master has given EIO. Its side of the
+/// pty closes just before it can be reaped, so it's given `EXIT_WAIT` to
+/// finish exiting rather than blocking on one that kept running.
+fn wait_child(child: Pid) -> nix::Result<WaitStatus> {
+    let deadline = Instant::now() + EXIT_WAIT;
+    loop {
+        match waitpid(child, Some(WaitPidFlag::WNOHANG)) {
+            Ok(WaitStatus::StillAlive) if Instant::now() < deadline => {
+                std::thread::sleep(Duration::from_millis(5))
+            }
+            Err(Errno::EINTR) => continue,
+            result => return result,
+        }
+    }
+}
+
+/// How the session ended, as shown below its output.
+fn exit_message(status: nix::Result<WaitStatus>) -> String {
+    match status {
+        Ok(WaitStatus::Exited(_, code)) => format!("[process exited with code {}]", code),
+        Ok(WaitStatus::Signaled(_, signal, _)) => format!("[process killed by {}]", signal),
+        Ok(status) => {
+            println!("Session ended: {:?}", status);
+            "[process exited]".to_string()
+        }
+        Err(e) => {
+            println!("Session ended, failed to wait for the child: {}", e);
+            "[process exited]".to_string()
+        }
+    }
+}
+
+/// Writes `pending` with `write` until it's empty or the writer would block,
+/// retrying writes interrupted by a signal. What wasn't written is left in
+/// `pending`, in order.
+fn drain_input(pending: &mut Vec<u8>, mut write: impl FnMut(&[u8]) -> nix::Result<usize>) {
+    while !pending.is_empty() {
+        match write(pending) {
+            Ok(written) => {
+                pending.drain(..written);
+            }
+            Err(Errno::EINTR) => continue,
+            // The pty's input queue is full until the program reads
+            Err(Errno::EAGAIN) => break,
+            // The next read sees the same error and ends the session
+            Err(e) if master_gone(e) => {
+                pending.clear();
+                break;
+            }
+            Err(e) => {
+                println!("Failed to write to terminal: {}", e);
+                pending.clear();
+                break;
+            }
+        }
+    }
+}
+
+/// Whether an error on the pty master means the session is over: EIO once
+/// the other side of the pty is gone because the program has exited, EBADF
+/// if the master itself was closed.
+fn master_gone(e: Errno) -> bool {
+    matches!(e, Errno::EIO | Errno::EBADF)
+}
+
+/// Typed input as it's kept in the history. A paste can hold several lines,
+/// each run as its own command, so each becomes its own entry. Other
+/// control characters are dropped and surrounding whitespace trimmed. With
+/// `collapse` set, runs of spaces inside a command become one space too.
+fn history_entries(input: &str, collapse: bool) -> Vec<String> {
+    input
+        .split(['\r', '\n'])
+        .map(|line| {
+            let line: String = line.chars().filter(|c| !c.is_control()).collect();
+            if collapse {
+                line.split_whitespace().collect::<Vec<_>>().join(" ")
+            } else {
+                line.trim().to_string()
+            }
+        })
+        .filter(|command| !command.is_empty())
+        .collect()
+}
+
+/// Keeps the printable part of the raw pty output.
+fn clean_output(content: &[u8]) -> String {
+    let cleaned_output: String = content
+        .iter()
+        .filter(|&&c| c.is_ascii_graphic() || c.is_ascii_whitespace())
+        .map(|&c| c as char)
+        .collect();
+
+    cleaned_output.replace("[?2004h", "").replace("[?2004l", "")
+}
+
+/// `content` without the blank lines after its last visible character. Blank
+/// lines in between are kept.
+fn trim_blank_lines(content: &[u8]) -> &[u8] {
+    // Anything but whitespace is visible, non-ASCII text included
+    let Some(last) = content.iter().rposition(|c| !c.is_ascii_whitespace()) else {
+        return &[];
+    };
+    let content = match content[last..].iter().position(|&c| c == b'\n') {
+        Some(end) => &content[..last + end],
+        None => content,
+    };
+    content.strip_suffix(b"\r").unwrap_or(content)
+}
+
+/// The last `count` lines of the printable output.
+fn last_lines(content: &[u8], count: usize) -> String {
+    let cleaned_output = clean_output(content);
+    let lines: Vec<&str> = cleaned_output.lines().collect();
+    lines[lines.len().saturating_sub(count)..].join("\n")
+}
+
+/// Lays out the pty output. With `overstrike` set, the nroff `X\bX` and `_\bX`
+/// sequences `man` falls back to on a dumb terminal become bold and underlined
+/// characters. With `line_numbers` set, each line starts with its number in
+/// the scroll back, right aligned to the given width and in the given color.
+fn layout_output(
+    content: &[u8],
+    overstrike: bool,
+    line_numbers: Option<(usize, egui::Color32)>,
+    style: &egui::Style,
+) -> egui::text::LayoutJob {
+    let font_id = egui::TextStyle::Monospace.resolve(style);
+    let plain = egui::TextFormat::simple(font_id.clone(), style.visuals.text_color());
+    let bold = egui::TextFormat::simple(font_id, style.visuals.strong_text_color());
+    let underline = egui::TextFormat {
+        underline: egui::Stroke::new(1.0, style.visuals.text_color()),
+        ..plain.clone()
+    };
+
+    let mut job = egui::text::LayoutJob::default();
+    // Overstrikes never span lines, so lines are laid out one at a time
+    for (number, line) in content.split(|&c| c == b'\n').enumerate() {
+        if number > 0 {
+            job.append("\n", 0.0, plain.clone());
+        }
+        if let Some((width, color)) = line_numbers {
+            let gutter = egui::TextFormat {
+                color,
+                ..plain.clone()
+            };
+            job.append(&format!("{:>width$} ", number + 1), 0.0, gutter);
+        }
+        let mut plain_start = 0;
+        let mut i = 0;
+        while overstrike && i + 2 < line.len() {
+            let format = match (line[i], Control::from_byte(line[i + 1]), line[i + 2]) {
+                (b'_', Some(Control::Backspace), c) if c.is_ascii_graphic() => &underline,
+                (a, Some(Control::Backspace), c) if a == c && c.is_ascii_graphic() => &bold,
+                _ => {
+                    i += 1;
+                    continue;
+                }
+            };
+            job.append(&clean_output(&line[plain_start..i]), 0.0, plain.clone());
+            job.append(&(line[i + 2] as char).to_string(), 0.0, format.clone());
+            i += 3;
+            plain_start = i;
+        }
+        job.append(&clean_output(&line[plain_start..]), 0.0, plain.clone());
+    }
+    job
+}
+
+/// A `<pre>` page with the laid out output. Neighbouring sections styled the
+/// same share one span, and text in the default style gets none.
+fn output_to_html(job: &egui::text::LayoutJob, style: &egui::Style) -> String {
+    let css_color =
+        |color: egui::Color32| format!("#{:02x}{:02x}{:02x}", color.r(), color.g(), color.b());
+    let span_style = |format: &egui::TextFormat| {
+        let mut css = String::new();
+        if format.color != style.visuals.text_color() {
+            css += &format!("color:{};", css_color(format.color));
+        }
+        // Bold is only a color in egui, the browser can do better
+        if format.color == style.visuals.strong_text_color() {
+            css += "font-weight:bold;";
+        }
+        if format.underline.width > 0.0 {
+            css += "text-decoration:underline;";
+        }
+        css
+    };
+    let escape = |text: &str| {
+        text.replace('&', "&amp;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;")
+    };
+
+    let font_size = egui::TextStyle::Monospace.resolve(style).size;
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>Termion</title></head>\n\
+         <body style=\"margin:0\"><pre style=\"margin:0;padding:8px;background:{};color:{};\
+         font-family:monospace;font-size:{}px\">",
+        css_color(style.visuals.panel_fill),
+        css_color(style.visuals.text_color()),
+        font_size,
+    );
+    let mut run_style = String::new();
+    let mut run_text = String::new();
+    for section in &job.sections {
+        let css = span_style(&section.format);
+        if css != run_style {
+            html += &styled_run(&run_style, &escape(&run_text));
+            run_style = css;
+            run_text.clear();
+        }
+        run_text += &job.text[section.byte_range.clone()];
+    }
+    html += &styled_run(&run_style, &escape(&run_text));
+    html += "</pre></body></html>\n";
+    html
+}
+
+/// `text`, in a span when there is a `css` style to give it.
+fn styled_run(css: &str, text: &str) -> String {
+    if css.is_empty() || text.is_empty() {
+        text.to_string()
+    } else {
+        format!("<span style=\"{}\">{}</span>", css, text)
+    }
+}
+
+/// Seconds since the epoch, to give saved files distinct names.
+fn timestamp() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default()
+}
+
+/// Number of whole cells fitting in `size`, as (rows, cols). Never less than
+/// one of each, however small the window or odd the font.
+fn grid_size(size: egui::Vec2, character_size: (f32, f32)) -> (u16, u16) {
+    let rows = size.y / character_size.1.max(1.0);
+    let cols = size.x / character_size.0.max(1.0);
+    ((rows as u16).max(1), (cols as u16).max(1))
+}
+
+/// The grid size to send to the pty at `now`, if any, given the size last
+/// sent and the one waiting out the `debounce` in `pending`. A size is sent
+/// once it has held for the whole debounce, or straight away if nothing has
+/// been sent yet.
+fn settled_size(
+    sent: Option<(u16, u16)>,
+    pending: &mut Option<((u16, u16), Instant)>,
+    size: (u16, u16),
+    now: Instant,
+    debounce: Duration,
+) -> Option<(u16, u16)> {
+    if sent == Some(size) {
+        *pending = None;
+        return None;
+    }
+    if sent.is_some() {
+        let since = match *pending {
+            Some((pending_size, since)) if pending_size == size => since,
+            _ => {
+                *pending = Some((size, now));
+                now
+            }
+        };
+        if now - since < debounce {
+            return None;
+        }
+    }
+    *pending = None;
+    Some(size)
+}
+
+/// `grid` cut down to `--max-rows` and `--max-cols`, and whether it was.
+fn cap_grid((rows, cols): (u16, u16), config: &Config) -> ((u16, u16), bool) {
+    let capped = rows > config.max_rows || cols > config.max_cols;
+    (
+        (rows.min(config.max_rows), cols.min(config.max_cols)),
+        capped,
+    )
+}
+
+/// Lines a stepped mouse wheel movement of `delta` scrolls, however the
+/// wheel reports it: `scroll_lines` for each notch, or pixels and pages
+/// converted with the row and page height.
+fn wheel_lines(
+    unit: egui::MouseWheelUnit,
+    delta: f32,
+    scroll_lines: f32,
+    row_height: f32,
+    page_lines: f32,
+) -> f32 {
+    match unit {
+        egui::MouseWheelUnit::Line => delta * scroll_lines,
+        egui::MouseWheelUnit::Point => delta / row_height.max(1.0),
+        egui::MouseWheelUnit::Page => delta * page_lines,
+    }
 }
 
 fn char_to_cursor_offset(
@@ -117,147 +1135,656 @@ fn char_to_cursor_offset(
 }
 
 impl eframe::App for Termion {
-    fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
-        if self.character_size.is_none() {
-            self.character_size = Some(get_char_size(ctx));
-            println!("self.character_size: {:?}", self.character_size);
+    fn save(&mut self, storage: &mut dyn eframe::Storage) {
+        eframe::set_value(storage, WRAP_KEY, &self.wrap);
+        // A run without --restore-lines leaves the saved tail for the next
+        // run with it
+        if self.config.restore_lines == 0 {
+            return;
+        }
+        let mut content = self.buf.as_slice();
+        if self.config.trim_blank_lines {
+            content = trim_blank_lines(content);
         }
+        eframe::set_value(
+            storage,
+            RESTORE_KEY,
+            &last_lines(content, self.config.restore_lines),
+        );
+    }
 
-        let mut buf = vec![0u8; 4096];
-        // println!(":");
-        match nix::unistd::read(self.fd.as_raw_fd(), &mut buf) {
-            Ok(0) => {
-                println!("EOF reached");
-                return;
-            }
-            Ok(read_size) => {
-                let incoming = &buf[0..read_size];
-                for c in incoming {
-                    match c {
-                        b'\n' => self.cursor_pos = (0, 1 + self.cursor_pos.1),
-                        _ => self.cursor_pos = (1 + self.cursor_pos.0, self.cursor_pos.1),
-                    }
-                }
-                self.buf.extend_from_slice(incoming);
+    /// Takes the mouse wheel over when scrolling is stepped, so egui doesn't
+    /// smooth it. Ctrl+wheel is left for zooming.
+    fn raw_input_hook(&mut self, _ctx: &egui::Context, raw_input: &mut egui::RawInput) {
+        if self.config.smooth_scroll {
+            return;
+        }
+        let row_height = self.character_size.map_or(1.0, |size| size.1);
+        let page_lines = self.grid_size.map_or(1.0, |(rows, _)| rows as f32);
+        raw_input.events.retain(|event| match event {
+            egui::Event::MouseWheel {
+                unit,
+                delta,
+                modifiers,
+            } if !modifiers.ctrl && !modifiers.command => {
+                // Wheeling up scrolls back, towards smaller offsets
+                self.wheel_lines -= wheel_lines(
+                    *unit,
+                    delta.y,
+                    self.config.scroll_lines,
+                    row_height,
+                    page_lines,
+                );
+                false
             }
-            Err(e) => {
-                if e != Errno::EAGAIN {
-                    println!("Read Failed due to: {}", e);
-                    // exit(1); // Kill the emulator if there is error;
-                } else {
-                    // println!("-");
-                }
+            _ => true,
+        });
+    }
+
+    fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
+        // Measured before anything else so the first frame already lays out,
+        // sizes the pty and places the cursor with the real cell size.
+        let character_size = self.character_size(ctx);
+
+        // eframe sizes the window from the last run when there is one
+        if let Some(size) = self.initial_size.take() {
+            ctx.send_viewport_cmd(egui::ViewportCommand::InnerSize(size));
+        }
+
+        if ctx.input_mut(|input_state| input_state.consume_shortcut(&JUMP_TO_CURSOR)) {
+            self.jump_to_cursor = true;
+        }
+        if ctx.input_mut(|input_state| input_state.consume_shortcut(&UNLOCK_KEYBOARD)) {
+            self.key_modes.keyboard_locked = false;
+        }
+        if ctx.input_mut(|input_state| input_state.consume_shortcut(&TOGGLE_WRAP)) {
+            self.wrap = !self.wrap;
+            self.jump_to_cursor = true;
+        }
+        if ctx.input_mut(|input_state| input_state.consume_shortcut(&SCREENSHOT)) {
+            ctx.send_viewport_cmd(egui::ViewportCommand::Screenshot(Default::default()));
+        }
+        if ctx.input_mut(|input_state| input_state.consume_shortcut(&QUICK_COMMAND)) {
+            self.quick_command = match self.quick_command {
+                Some(_) => None,
+                None => Some(String::new()),
+            };
+        }
+        if ctx.input_mut(|input_state| input_state.consume_shortcut(&EXPORT_HTML)) {
+            self.save_html(&ctx.style());
+        }
+        let screenshot = ctx.input(|input_state| {
+            input_state.events.iter().find_map(|event| match event {
+                egui::Event::Screenshot { image, .. } => Some(image.clone()),
+                _ => None,
+            })
+        });
+        if let Some(screen) = screenshot {
+            self.save_screenshot(&screen, ctx.pixels_per_point());
+        }
+
+        if !self.exited {
+            if self.read_output() {
+                self.flush_input();
+            } else {
+                self.end_session();
             }
         }
 
+        // Typing goes to the quick command bar for as long as it's open,
+        // including the Enter or Escape that closes it
+        let quick_command_open = self.quick_command.is_some();
+        self.show_quick_command(ctx);
+
         // Side panel remains the same...
-        egui::SidePanel::right("history_panel")
+        let mut clicked = None;
+        egui::SidePanel::right(HISTORY_PANEL_ID)
             .min_width(100.0)
+            .default_width(HISTORY_PANEL_WIDTH)
             .show(ctx, |ui| {
                 ui.heading("Command History");
                 ui.separator();
                 for cmd in &self.command_history {
-                    if ui.button(cmd).clicked() {
+                    if ui.button(cmd).clicked() && self.accepts_input() {
                         println!("Clicked:: {}", cmd);
-                        self.current_command.clear();
-                        let cmd_with_newline = format!("{}\n", cmd);
-                        let bytes = cmd_with_newline.as_bytes();
-                        let mut to_write: &[u8] = &bytes;
-                        while to_write.len() > 0 {
-                            match nix::unistd::write(self.fd.as_fd(), to_write) {
-                                Ok(written) => to_write = &to_write[written..],
-                                Err(e) => {
-                                    println!("Failed to write command to terminal: {}", e);
-                                    break;
-                                }
-                            }
-                        }
-                        println!("Executed command from sidepanel: {}", cmd);
+                        clicked = Some(cmd.clone());
                     }
                 }
             });
+        if let Some(cmd) = clicked {
+            self.current_command.clear();
+            let cmd_with_newline = format!("{}\n", cmd);
+            self.write_pty(cmd_with_newline.as_bytes());
+            println!("Executed command from sidepanel: {}", cmd);
+        }
 
-        let binding = self.buf.clone();
-        let mut cleaned_output: String = binding
-            .iter()
-            .filter(|&&c| c.is_ascii_graphic() || c.is_ascii_whitespace())
-            .map(|&c| c as char)
-            .collect();
-
-        cleaned_output = cleaned_output.replace("[?2004h", "").replace("[?2004l", "");
+        // The gutter grows once the numbers outgrow --line-numbers
+        let gutter_width = self.config.line_numbers.map(|width| {
+            let lines = 1 + self.buf.iter().filter(|&&c| c == b'\n').count();
+            width.max(lines.to_string().len())
+        });
+        let line_numbers = gutter_width.map(|width| {
+            let color = match self.config.line_number_color {
+                Some([r, g, b]) => egui::Color32::from_rgb(r, g, b),
+                None => ctx.style().visuals.weak_text_color(),
+            };
+            (width, color)
+        });
+        let output = layout_output(
+            &self.buf,
+            self.config.overstrike,
+            line_numbers,
+            &ctx.style(),
+        );
+        // Columns taken by the line numbers and the space after them
+        let gutter_cols = gutter_width.map_or(0, |width| width + 1);
 
         egui::CentralPanel::default().show(ctx, |ui| {
-            egui::ScrollArea::both()
+            self.terminal_rect = ui.max_rect();
+            let (rows, cols) = grid_size(ui.available_size(), character_size);
+            let cols = cols.saturating_sub(gutter_cols as u16).max(1);
+            let ((rows, cols), capped) = cap_grid((rows, cols), &self.config);
+            self.resize(ctx, rows, cols);
+
+            let mut scroll_area = egui::ScrollArea::both()
                 .auto_shrink([false; 2]) // Prevent shrinking; ensures resizing works
-                .stick_to_bottom(true) // For large commands, helps keep ip part in focus
-                .show(ui, |ui| {
-                    ui.input(|input_state| {
-                        for event in &input_state.events {
-                            let text = match event {
-                                egui::Event::Text(text) => {
-                                    self.current_command.push_str(text);
-                                    text
-                                }
-                                egui::Event::Key { key, pressed, .. } => match key {
+                .stick_to_bottom(self.config.scroll_on_output); // For large commands, helps keep ip part in focus
+            if self.config.smooth_scroll {
+                ctx.options_mut(|options| {
+                    options.line_scroll_speed = self.config.scroll_lines * character_size.1
+                });
+            } else {
+                // Whole lines of wheel movement jump there this frame. The
+                // wheel events never reach egui, so sticking to the bottom is
+                // turned off here or it would undo the step. egui sticks again
+                // by itself once the offset is back at the end.
+                let lines = self.wheel_lines.trunc();
+                if lines != 0.0 {
+                    self.wheel_lines -= lines;
+                    let offset = self.scroll_offset + lines * character_size.1;
+                    scroll_area = scroll_area
+                        .vertical_scroll_offset(offset.max(0.0))
+                        .stick_to_bottom(false);
+                }
+            }
+            let scrolled = scroll_area.show(ui, |ui| {
+                if capped {
+                    // Wrap at the capped width, the rest is left empty
+                    ui.set_max_width((cols as usize + gutter_cols) as f32 * character_size.0);
+                }
+                ui.input(|input_state| {
+                    for event in &input_state.events {
+                        // Typing is dropped after the program exits, or while it has
+                        // locked the keyboard (KAM)
+                        if !self.accepts_input() || quick_command_open {
+                            break;
+                        }
+                        let bytes: Vec<u8> = match event {
+                            egui::Event::Text(text) if input_state.modifiers.alt => {
+                                keys::encode_meta(text, self.key_modes.meta)
+                            }
+                            egui::Event::Text(text) => {
+                                self.current_command.push_str(text);
+                                text.as_bytes().to_vec()
+                            }
+                            egui::Event::Paste(text) => {
+                                self.current_command.push_str(text);
+                                keys::encode_paste(text, &self.key_modes)
+                            }
+                            // egui turns Ctrl+C and Ctrl+X into clipboard events
+                            egui::Event::Copy => vec![0x03],
+                            egui::Event::Cut => vec![0x18],
+                            // Key repeats also arrive as presses, with
+                            // `repeat` set, so a held arrow or Backspace
+                            // sends its sequence at the OS repeat rate
+                            egui::Event::Key {
+                                key,
+                                pressed: true,
+                                modifiers,
+                                ..
+                            } => {
+                                match key {
                                     egui::Key::Enter => {
-                                        if !self.current_command.trim().is_empty() {
-                                            self.command_history.push(self.current_command.clone());
-                                        }
+                                        let entries = history_entries(
+                                            &self.current_command,
+                                            self.config.collapse_history_spaces,
+                                        );
+                                        self.command_history.extend(entries);
                                         self.current_command.clear();
-                                        "\n"
                                     }
                                     egui::Key::Backspace => {
-                                        println!("Hello world");
-                                        if *pressed && !self.current_command.is_empty() {
-                                            self.current_command.pop();
-                                            let backspace_char = b'\x08'; // ASCII backspace character
-                                            nix::unistd::write(self.fd.as_fd(), &[backspace_char])
-                                                .unwrap();
-                                            ""
-                                            // "\x08" // ASCII backspace character, TODO: Get ansi escape codes to work, the backspace is working but not reflected in the UI
-                                            // "\x7F" // Delete character (DEL)
-                                        } else {
-                                            ""
-                                        }
+                                        self.current_command.pop();
                                     }
-                                    _ => "",
-                                },
-                                _ => "",
-                            };
-
-                            // let temp_text = &text.replace("[?2004h", "").replace("[?2004l", "");
-                            let temp_text = &text;
-                            let bytes = temp_text.as_bytes();
-
-                            let mut to_write: &[u8] = &bytes;
-                            while to_write.len() > 0 {
-                                let written =
-                                    nix::unistd::write(self.fd.as_fd(), to_write).unwrap();
-                                to_write = &to_write[written..];
+                                    _ => {}
+                                }
+                                keys::encode_key(*key, *modifiers, &self.key_modes)
+                                    .unwrap_or_default()
                             }
+                            _ => Vec::new(),
+                        };
+
+                        if !bytes.is_empty() {
+                            self.last_activity = Instant::now();
+                            if self.config.scroll_on_keystroke {
+                                self.jump_to_cursor = true;
+                            }
+                        }
+                        self.write_pty(&bytes);
+                        if self.key_modes.local_echo {
+                            self.push_output(&bytes);
                         }
-                    });
-                    let response = ui.label(cleaned_output);
+                    }
+                });
+                let wrap_mode = if self.wrap {
+                    egui::TextWrapMode::Wrap
+                } else {
+                    egui::TextWrapMode::Extend
+                };
+                if !self.restored_output.is_empty() {
+                    // Greyed out and fenced off, it's only a reminder of
+                    // where the last session left off
+                    ui.add(
+                        egui::Label::new(egui::RichText::new(&self.restored_output).weak())
+                            .wrap_mode(wrap_mode),
+                    );
+                    ui.separator();
+                }
+                let response = ui.add(egui::Label::new(output).wrap_mode(wrap_mode));
 
-                    let left = response.rect.left();
-                    let bottom = response.rect.bottom();
+                let left = response.rect.left() + gutter_cols as f32 * character_size.0;
+                let bottom = response.rect.bottom();
 
-                    let painter = ui.painter();
-                    let character_size = self.character_size.as_ref().unwrap();
-                    let (x_offset, y_offset) =
-                        char_to_cursor_offset(&self.cursor_pos, character_size, &self.buf);
+                let painter = ui.painter();
+                let (x_offset, y_offset) =
+                    char_to_cursor_offset(&self.cursor_pos, &character_size, &self.buf);
+                let target = egui::vec2(x_offset, y_offset);
+                let painted = self.animate_cursor(target, character_size);
+                if painted != target {
+                    ctx.request_repaint_after(Duration::from_secs_f32(
+                        1.0 / self.config.max_fps as f32,
+                    ));
+                }
 
+                let cursor_size = egui::vec2(character_size.0, character_size.1);
+                let cursor_rect =
+                    egui::Rect::from_min_size(egui::pos2(left, bottom) + target, cursor_size);
+                if !self.exited {
                     painter.rect_filled(
-                        egui::Rect::from_min_size(
-                            egui::pos2(left + x_offset, bottom + y_offset),
-                            egui::vec2(character_size.0, character_size.1),
-                        ),
+                        egui::Rect::from_min_size(egui::pos2(left, bottom) + painted, cursor_size),
                         0.0,
                         egui::Color32::GREEN,
                     );
-                    println!("{} {}", x_offset, y_offset);
-                    ctx.request_repaint(); // Explicitly request a repaint
-                });
+                }
+                if self.jump_to_cursor {
+                    // Aligning to the bottom lets stick_to_bottom take over again
+                    ui.scroll_to_rect(cursor_rect, Some(egui::Align::Max));
+                    self.jump_to_cursor = false;
+                }
+                println!("{} {}", x_offset, y_offset);
+            });
+            self.scroll_offset = scrolled.state.offset.y;
         });
+
+        // The pty is polled once per frame, so keep frames coming: at the
+        // capped rate while output is flowing, slower once things go quiet.
+        // Past the idle timeout no frames are asked for at all, egui wakes up
+        // for input and the wakeup thread for output.
+        // Once the program has exited there's nothing left to poll
+        let idle = self.last_activity.elapsed();
+        if self.exited {
+            self.suspended = true;
+        } else if let (Some(idle_timeout), Some(wakeup)) = (self.config.idle_timeout, &self.wakeup)
+        {
+            // Input still waiting for the pty is only sent from a frame
+            if idle >= idle_timeout && self.pending_input.is_empty() {
+                if !self.suspended {
+                    self.suspended = wakeup.send(()).is_ok();
+                }
+            } else {
+                self.suspended = false;
+            }
+        }
+        if !self.suspended {
+            let delay = if idle < ACTIVE_PERIOD {
+                Duration::from_secs_f32(1.0 / self.config.max_fps as f32)
+            } else {
+                IDLE_POLL_INTERVAL
+            };
+            ctx.request_repaint_after(delay);
+        }
+
+        self.show_close_prompt(ctx);
+        if self.exited && self.config.close_on_exit {
+            ctx.send_viewport_cmd(egui::ViewportCommand::Close);
+        }
+
+        // The terminal keeps the keyboard: egui moves focus on Tab and the
+        // arrows, and a focused history button would then be clicked by the
+        // Enter or Space meant for the shell. The quick command bar is the
+        // exception while it's open.
+        if self.quick_command.is_none() {
+            ctx.memory_mut(|memory| {
+                if let Some(id) = memory.focused() {
+                    memory.surrender_focus(id);
+                }
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The non-empty sections of laid out output, as text and whether each
+    /// is bold and underlined.
+    fn styled_sections(job: &egui::text::LayoutJob) -> Vec<(&str, bool, bool)> {
+        let style = egui::Style::default();
+        job.sections
+            .iter()
+            .filter(|section| !section.byte_range.is_empty())
+            .map(|section| {
+                (
+                    &job.text[section.byte_range.clone()],
+                    section.format.color == style.visuals.strong_text_color(),
+                    section.format.underline.width > 0.0,
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn overstrikes_are_bold_and_underlined() {
+        let style = egui::Style::default();
+        let job = layout_output(b"x a\x08a _\x08b y", true, None, &style);
+        assert_eq!(job.text, "x a b y");
+        assert_eq!(
+            styled_sections(&job),
+            [
+                ("x ", false, false),
+                ("a", true, false),
+                (" ", false, false),
+                ("b", false, true),
+                (" y", false, false),
+            ]
+        );
+    }
+
+    #[test]
+    fn overstrikes_are_left_alone_when_off() {
+        let style = egui::Style::default();
+        let job = layout_output(b"a\x08a", false, None, &style);
+        assert_eq!(job.text, "aa");
+        assert_eq!(styled_sections(&job), [("aa", false, false)]);
+    }
+
+    #[test]
+    fn html_export_has_the_plain_text() {
+        let style = egui::Style::default();
+        let job = layout_output(
+            b"a < b && c\n_\x08ux\x08x <tag>",
+            true,
+            Some((2, egui::Color32::RED)),
+            &style,
+        );
+        let html = output_to_html(&job, &style);
+
+        let body = &html[html.find("px\">").unwrap() + 4..html.find("</pre>").unwrap()];
+        let mut text = String::new();
+        let mut in_tag = false;
+        for c in body.chars() {
+            match c {
+                '<' => in_tag = true,
+                '>' if in_tag => in_tag = false,
+                _ if !in_tag => text.push(c),
+                _ => {}
+            }
+        }
+        let text = text
+            .replace("&lt;", "<")
+            .replace("&gt;", ">")
+            .replace("&amp;", "&");
+        assert_eq!(text, job.text);
+        assert_eq!(text, " 1 a < b && c\n 2 ux <tag>");
+        assert!(html.contains("font-weight:bold;\">x</span>"));
+        assert!(html.contains("text-decoration:underline;\">u</span>"));
+    }
+
+    #[test]
+    fn cell_is_measured_again_for_a_new_font_or_scale() {
+        let measured = ((7.0, 14.0), 1.0);
+        assert!(!needs_measuring(measured, ((7.0, 14.0), 1.0)));
+        // Moved to a HiDPI monitor, the probe in points can stay the same
+        assert!(needs_measuring(measured, ((7.0, 14.0), 2.0)));
+        assert!(needs_measuring(measured, ((8.0, 14.0), 1.0)));
+        assert!(needs_measuring(measured, ((7.0, 16.0), 1.0)));
+    }
+
+    #[test]
+    fn grid_size_is_never_empty() {
+        assert_eq!(grid_size(egui::vec2(800.0, 0.0), (8.0, 16.0)), (1, 100));
+        assert_eq!(grid_size(egui::Vec2::ZERO, (8.0, 16.0)), (1, 1));
+        assert_eq!(grid_size(egui::vec2(800.0, 480.0), (0.0, 0.0)), (480, 800));
+        assert_eq!(grid_size(egui::vec2(805.0, 490.0), (8.0, 16.0)), (30, 100));
+    }
+
+    #[test]
+    fn window_size_fits_the_grid() {
+        let character_size = (7.24, 13.97);
+        // What the central panel is left with once the history panel and
+        // the frame around the terminal have taken their share
+        let panels = egui::vec2(HISTORY_PANEL_WIDTH + 16.0, 16.0);
+        for (cols, rows) in [(80, 24), (132, 50), (1, 1)] {
+            let size = window_size(cols, rows, character_size);
+            assert_eq!(grid_size(size - panels, character_size), (rows, cols));
+        }
+    }
+
+    #[test]
+    fn only_the_settled_size_is_sent() {
+        let debounce = Duration::from_millis(100);
+        let start = Instant::now();
+        let at = |millis| start + Duration::from_millis(millis);
+        let mut pending = None;
+
+        // The first size goes out straight away
+        assert_eq!(
+            settled_size(None, &mut pending, (24, 80), at(0), debounce),
+            Some((24, 80))
+        );
+
+        // A drag sends nothing until the last size has held
+        let sent = Some((24, 80));
+        for (millis, size) in [(0, (25, 82)), (30, (27, 85)), (60, (30, 90))] {
+            assert_eq!(
+                settled_size(sent, &mut pending, size, at(millis), debounce),
+                None
+            );
+        }
+        assert_eq!(
+            settled_size(sent, &mut pending, (30, 90), at(159), debounce),
+            None
+        );
+        assert_eq!(
+            settled_size(sent, &mut pending, (30, 90), at(160), debounce),
+            Some((30, 90))
+        );
+        assert!(pending.is_none());
+
+        // The size already sent is never sent again
+        let sent = Some((30, 90));
+        for millis in [200, 400, 1000] {
+            assert_eq!(
+                settled_size(sent, &mut pending, (30, 90), at(millis), debounce),
+                None
+            );
+        }
+        assert!(pending.is_none());
+    }
+
+    #[test]
+    fn huge_grids_are_capped() {
+        let config = Config {
+            max_rows: 100,
+            max_cols: 200,
+            ..Config::default()
+        };
+        // An 8K display with a 1 point font
+        let grid = grid_size(egui::vec2(7680.0, 4320.0), (0.6, 1.0));
+        assert_eq!(cap_grid(grid, &config), ((100, 200), true));
+        assert_eq!(cap_grid((24, 80), &config), ((24, 80), false));
+        assert_eq!(cap_grid((24, 500), &config), ((24, 200), true));
+    }
+
+    #[test]
+    fn wheel_notches_scroll_scroll_lines() {
+        let line = egui::MouseWheelUnit::Line;
+        assert_eq!(wheel_lines(line, 1.0, 3.0, 14.0, 24.0), 3.0);
+        assert_eq!(wheel_lines(line, -2.0, 5.0, 14.0, 24.0), -10.0);
+        let point = egui::MouseWheelUnit::Point;
+        assert_eq!(wheel_lines(point, 28.0, 3.0, 14.0, 24.0), 2.0);
+        assert_eq!(
+            wheel_lines(egui::MouseWheelUnit::Page, 1.0, 3.0, 14.0, 24.0),
+            24.0
+        );
+    }
+
+    #[test]
+    fn trailing_blank_lines_are_trimmed() {
+        assert_eq!(trim_blank_lines(b"a\n\nb\r\n\n  \n"), b"a\n\nb");
+        assert_eq!(
+            trim_blank_lines("a\n日本\r\n\n".as_bytes()),
+            "a\n日本".as_bytes()
+        );
+        assert_eq!(trim_blank_lines(b"a\n\nb"), b"a\n\nb");
+        assert_eq!(trim_blank_lines(b" \n\t\n"), b"");
+        assert_eq!(trim_blank_lines(b""), b"");
+    }
+
+    #[test]
+    fn history_entries_drop_control_characters() {
+        assert_eq!(history_entries("ls\x07 -la  \r", false), ["ls -la"]);
+        assert_eq!(history_entries("ls   -la", false), ["ls   -la"]);
+        assert_eq!(history_entries("ls   -la", true), ["ls -la"]);
+    }
+
+    #[test]
+    fn answerback_reply_answers_each_enq() {
+        assert_eq!(
+            answerback_reply(b"a\x05b\x05", "Termion"),
+            b"TermionTermion"
+        );
+        assert!(answerback_reply(b"no enquiry", "Termion").is_empty());
+        assert!(answerback_reply(b"\x05", "").is_empty());
+    }
+
+    #[test]
+    fn paste_cut_off_by_a_full_pty_is_sent_whole() {
+        let modes = KeyModes {
+            app_cursor: false,
+            bracketed_paste: true,
+            keyboard_locked: false,
+            local_echo: false,
+            meta: config::MetaMode::Escape,
+        };
+        let paste = keys::encode_paste("echo hi", &modes);
+        let mut pending = paste.clone();
+        let mut sent = Vec::new();
+
+        // The pty takes the start marker and a little more, then fills up
+        let mut room = 9;
+        let mut pty = |bytes: &[u8]| {
+            if room == 0 {
+                return Err(Errno::EAGAIN);
+            }
+            let written = bytes.len().min(room).min(4);
+            room -= written;
+            sent.extend_from_slice(&bytes[..written]);
+            Ok(written)
+        };
+        drain_input(&mut pending, &mut pty);
+        assert_eq!(sent, &paste[..9]);
+        assert_eq!(pending, &paste[9..]);
+
+        // Typing queued behind the paste goes out after it, once there's room
+        pending.extend_from_slice(b"\r");
+        drain_input(&mut pending, |bytes: &[u8]| {
+            sent.extend_from_slice(bytes);
+            Ok(bytes.len())
+        });
+        assert!(pending.is_empty());
+        assert_eq!(sent, [&paste[..], b"\r"].concat());
+    }
+
+    /// Runs the program `config` asks for in a pty, the way `main` does, and
+    /// returns its pid and the output it left once it was gone.
+    fn run_in_pty(config: &Config) -> (Pid, String) {
+        let (child, master) = match unsafe { forkpty(None, None) }.unwrap() {
+            ForkptyResult::Parent { child, master } => (child, master),
+            ForkptyResult::Child => run_child(config),
+        };
+        let mut output = Vec::new();
+        let mut buf = [0u8; 4096];
+        loop {
+            match nix::unistd::read(master.as_raw_fd(), &mut buf) {
+                Ok(0) => break,
+                Ok(read_size) => output.extend_from_slice(&buf[..read_size]),
+                Err(Errno::EINTR) => continue,
+                Err(e) if master_gone(e) => break,
+                Err(e) => panic!("Read failed: {}", e),
+            }
+        }
+        (child, String::from_utf8_lossy(&output).into_owned())
+    }
+
+    /// The last line `pwd` printed in `dir`, or the home directory if `dir`
+    /// doesn't exist.
+    fn pwd_in(dir: std::path::PathBuf) -> String {
+        let config = Config {
+            cwd: Some(dir),
+            exec: Some(vec!["pwd".to_string()]),
+            ..Config::default()
+        };
+        let (child, output) = run_in_pty(&config);
+        waitpid(child, None).unwrap();
+        output.lines().last().unwrap_or_default().trim().to_string()
+    }
+
+    #[test]
+    fn cwd_is_where_the_program_starts() {
+        let dir = std::env::temp_dir().join(format!("termion-cwd-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let expected = dir.canonicalize().unwrap();
+        let pwd = pwd_in(dir.clone());
+        std::fs::remove_dir(&dir).unwrap();
+        assert_eq!(pwd, expected.to_str().unwrap());
+    }
+
+    #[test]
+    fn missing_cwd_falls_back_to_home() {
+        let home = std::path::PathBuf::from(std::env::var_os("HOME").unwrap());
+        let pwd = pwd_in(std::env::temp_dir().join("termion-no-such-dir"));
+        assert_eq!(pwd, home.canonicalize().unwrap().to_str().unwrap());
+    }
+
+    #[test]
+    fn exec_output_and_exit_code_are_kept() {
+        let config = Config {
+            exec: Some(["sh", "-c", "echo done; exit 3"].map(String::from).to_vec()),
+            ..Config::default()
+        };
+        let (child, output) = run_in_pty(&config);
+        assert_eq!(output.lines().last().map(str::trim), Some("done"));
+        assert_eq!(
+            exit_message(wait_child(child)),
+            "[process exited with code 3]"
+        );
+    }
+
+    #[test]
+    fn history_entries_split_pasted_lines() {
+        assert_eq!(history_entries("ls\npwd", false), ["ls", "pwd"]);
+        assert_eq!(history_entries("ls\r\n\r\n", false), ["ls"]);
+        assert!(history_entries(" \r\n", false).is_empty());
     }
 }
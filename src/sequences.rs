@@ -0,0 +1,247 @@
+/// A control character that does something, apart from the whitespace that
+/// is laid out as text.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Control {
+    /// LF: moves the estimated cursor to the start of the next line.
+    LineFeed,
+    /// ENQ: answered with the answerback.
+    Enquiry,
+    /// BS: only acted on inside nroff style overstrikes.
+    Backspace,
+}
+
+impl Control {
+    const ALL: [Control; 3] = [Control::LineFeed, Control::Enquiry, Control::Backspace];
+
+    fn byte(self) -> u8 {
+        match self {
+            Control::LineFeed => 0x0a,
+            Control::Enquiry => 0x05,
+            Control::Backspace => 0x08,
+        }
+    }
+
+    fn description(self) -> &'static str {
+        match self {
+            Control::LineFeed => "LF   moves the cursor to the next line",
+            Control::Enquiry => "ENQ  sends the --answerback reply",
+            Control::Backspace => "BS   makes X\\bX bold and _\\bX underlined",
+        }
+    }
+
+    pub fn from_byte(byte: u8) -> Option<Control> {
+        Self::ALL.into_iter().find(|control| control.byte() == byte)
+    }
+}
+
+/// A mode set with `CSI Pm h` and reset with `CSI Pm l`, or `CSI ? Pm h` and
+/// `CSI ? Pm l` for DEC private modes, that changes what the terminal does.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Mode {
+    /// DECCKM: arrows and Home/End send `SS3` instead of `CSI` while set.
+    CursorKeys,
+    /// KAM: typing is ignored while set.
+    KeyboardLock,
+    /// SRM: typing is echoed locally while reset.
+    SendReceive,
+    /// Pastes are wrapped in `CSI 200~` .. `CSI 201~` while set.
+    BracketedPaste,
+}
+
+impl Mode {
+    const ALL: [Mode; 4] = [
+        Mode::CursorKeys,
+        Mode::KeyboardLock,
+        Mode::SendReceive,
+        Mode::BracketedPaste,
+    ];
+
+    /// Whether the mode is a DEC private one, and its number.
+    fn code(self) -> (bool, u16) {
+        match self {
+            Mode::CursorKeys => (true, 1),
+            Mode::KeyboardLock => (false, 2),
+            Mode::SendReceive => (false, 12),
+            Mode::BracketedPaste => (true, 2004),
+        }
+    }
+
+    fn description(self) -> &'static str {
+        match self {
+            Mode::CursorKeys => "DECCKM, application cursor keys",
+            Mode::KeyboardLock => "KAM, ignore typing while set, Ctrl+Shift+U unlocks",
+            Mode::SendReceive => "SRM, echo typing locally while reset",
+            Mode::BracketedPaste => "bracketed paste",
+        }
+    }
+
+    pub fn from_code(private: bool, number: u16) -> Option<Mode> {
+        Self::ALL
+            .into_iter()
+            .find(|mode| mode.code() == (private, number))
+    }
+}
+
+/// Bytes of earlier output scanned again with new output, so a mode change
+/// split across reads is still found.
+pub const MODE_LOOKBACK: usize = 15;
+
+/// The mode changes (`CSI Pm h`, `CSI ? Pm l`, ...) in `output`, in order, as
+/// `(private, mode, set)`.
+pub fn mode_changes(output: &[u8]) -> Vec<(bool, u16, bool)> {
+    let mut changes = Vec::new();
+    let mut rest = output;
+    while let Some(start) = rest.windows(2).position(|window| window == b"\x1b[") {
+        rest = &rest[start + 2..];
+        let private = rest.first() == Some(&b'?');
+        let params = &rest[private as usize..];
+        let params_len = params
+            .iter()
+            .take_while(|c| c.is_ascii_digit() || **c == b';')
+            .count();
+        let set = match params.get(params_len) {
+            Some(b'h') => true,
+            Some(b'l') => false,
+            _ => continue,
+        };
+        for param in params[..params_len].split(|&c| c == b';') {
+            let mode = std::str::from_utf8(param).ok().and_then(|p| p.parse().ok());
+            if let Some(mode) = mode {
+                changes.push((private, mode, set));
+            }
+        }
+    }
+    changes
+}
+
+/// Where `EscapeStripper` is in the output.
+#[derive(Default)]
+enum StripState {
+    #[default]
+    Text,
+    /// After ESC.
+    Escape,
+    /// In a `CSI` sequence, up to its final byte.
+    Csi,
+    /// In an `OSC`, `DCS`, `SOS`, `PM` or `APC` string, up to BEL or ST.
+    String,
+    /// After an ESC inside a string, which is ST if `\` follows.
+    StringEscape,
+}
+
+/// Strips escape sequences out of output read a piece at a time, keeping the
+/// printable text and whitespace. Sequences split across reads are followed
+/// from one piece to the next.
+#[derive(Default)]
+pub struct EscapeStripper {
+    state: StripState,
+}
+
+impl EscapeStripper {
+    pub fn strip(&mut self, output: &[u8]) -> Vec<u8> {
+        let mut text = Vec::new();
+        for &c in output {
+            self.state = match (&self.state, c) {
+                (_, 0x1b) if !matches!(self.state, StripState::String) => StripState::Escape,
+                (StripState::Text, _) => {
+                    if c.is_ascii_graphic() || c.is_ascii_whitespace() {
+                        text.push(c);
+                    }
+                    StripState::Text
+                }
+                (StripState::Escape, b'[') => StripState::Csi,
+                (StripState::Escape, b']' | b'P' | b'X' | b'^' | b'_') => StripState::String,
+                // Intermediate bytes, as in `ESC ( B`
+                (StripState::Escape, 0x20..=0x2f) => StripState::Escape,
+                (StripState::Escape, _) => StripState::Text,
+                (StripState::Csi, 0x40..=0x7e) => StripState::Text,
+                (StripState::Csi, _) => StripState::Csi,
+                (StripState::String, 0x07) => StripState::Text,
+                (StripState::String, 0x1b) => StripState::StringEscape,
+                (StripState::String, _) => StripState::String,
+                (StripState::StringEscape, b'\\') => StripState::Text,
+                (StripState::StringEscape, _) => StripState::String,
+            };
+        }
+        text
+    }
+}
+
+/// Prints the control characters and modes that are acted on, for
+/// `--list-sequences`.
+pub fn print_supported() {
+    println!("Control characters:");
+    for control in Control::ALL {
+        println!("  0x{:02x} {}", control.byte(), control.description());
+    }
+    println!("Modes (CSI h and CSI l):");
+    for mode in Mode::ALL {
+        let (private, number) = mode.code();
+        let code = format!("{}{}", if private { "?" } else { "" }, number);
+        println!("  {:<9} {}", code, mode.description());
+    }
+    println!(
+        "Nothing else is handled. Of the other control characters, CR, TAB, VT and \
+         FF are passed on to egui's text layout as whitespace and the rest are \
+         dropped from the display. Other escape sequences aren't interpreted: their \
+         ESC is dropped and the rest shows as text."
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mode_changes_are_found() {
+        assert_eq!(mode_changes(b"a\x1b[2hb"), [(false, 2, true)]);
+        assert_eq!(
+            mode_changes(b"\x1b[?2004;2l"),
+            [(true, 2004, false), (true, 2, false)]
+        );
+        assert_eq!(
+            mode_changes(b"\x1b[?1h\x1b[?1l"),
+            [(true, 1, true), (true, 1, false)]
+        );
+    }
+
+    #[test]
+    fn other_finals_are_not_mode_changes() {
+        assert!(mode_changes(b"\x1b[2J\x1b[12m\x1b[?25c").is_empty());
+        assert!(mode_changes(b"\x1b[2").is_empty());
+    }
+
+    #[test]
+    fn mode_changes_split_across_reads() {
+        // push_output scans the new output along with the end of the old,
+        // a change seen in full in both is applied twice, in order
+        let first = b"some earlier text\x1b[?20";
+        let second = b"04h more";
+        let scanned = [&first[first.len() - MODE_LOOKBACK..], &second[..]].concat();
+        assert!(mode_changes(first).is_empty());
+        assert_eq!(mode_changes(&scanned), [(true, 2004, true)]);
+    }
+
+    #[test]
+    fn keyboard_lock_is_kam() {
+        assert!(Mode::from_code(false, 2) == Some(Mode::KeyboardLock));
+        assert!(Mode::from_code(true, 2).is_none());
+    }
+
+    #[test]
+    fn stripper_removes_csi_and_osc() {
+        let mut stripper = EscapeStripper::default();
+        let output = b"\x1b]0;title\x07\x1b[01;34mdir\x1b[0m \x1b]2;x\x1b\\ok\x1b(B\r\n";
+        assert_eq!(stripper.strip(output), b"dir ok\r\n");
+    }
+
+    #[test]
+    fn stripper_follows_sequences_across_reads() {
+        let mut stripper = EscapeStripper::default();
+        assert_eq!(stripper.strip(b"a\x1b"), b"a");
+        assert_eq!(stripper.strip(b"[?20"), b"");
+        assert_eq!(stripper.strip(b"04hb\x1b]0;ti"), b"b");
+        assert_eq!(stripper.strip(b"tle\x1b"), b"");
+        assert_eq!(stripper.strip(b"\\c"), b"c");
+    }
+}